@@ -4,19 +4,54 @@
 //! stored in the database passed through [`Config::database`].
 //!
 //! The block import task receives blocks from other parts of the code (most likely the network)
-//! through [`ToBlockImport::Import`] messages, verifies if they are correct by executing them, and
-//! if so appends them to the head of the chain. Only blocks whose parent is the current head of
-//! the chain are considered, and the others discarded.
+//! through [`ToBlockImport::Import`] messages. Blocks that import on top of any recently-imported
+//! header (not just the current best block) are accepted; see [`tree_route`] for how the task
+//! reacts when such a side-chain overtakes the current best chain.
+//!
+//! Verification itself doesn't happen inline: incoming blocks are placed on a [`block_queue`] and
+//! verified by a pool of background workers, so that the task can keep accepting and queueing new
+//! blocks rather than blocking on a single, potentially slow, WASM execution. The actual database
+//! write still happens one block at a time, in parent order, once a block comes back verified.
+//!
+//! Rather than always replaying every block since genesis, the task can also be bootstrapped
+//! directly to a recent block by importing a snapshot of its full top trie; see [`snapshot_sync`].
+//!
+//! Separately from the best block, the task also tracks a *finalized* block: one that a GRANDPA
+//! justification has proven can never be reverted. See [`finality`] for how justifications are
+//! verified and the authority set they're checked against is tracked; reorgs are guaranteed to
+//! never retract a block at or below the finalized height.
+//!
+//! Other parts of the node can subscribe to be notified of every block the commit stage writes to
+//! the database, rather than having to poll [`ToBlockImport::BestBlockNumber`]; see [`notify`].
+//!
+//! Storage accessed while verifying and committing blocks goes through a bounded, eviction-capable
+//! cache rather than a full in-memory mirror of the top trie; see [`storage_cache`].
+
+mod block_queue;
+mod finality;
+mod notify;
+mod snapshot_sync;
+mod storage_cache;
+mod tree_route;
 
-use crate::{babe, block, block_import, database, executor, header, trie::calculate_root};
+use block_queue::{PendingImport, QueueStatus, UnverifiedQueue, VerifiedImport, VerifyingCounter};
+use finality::AuthoritySet;
+use notify::{ImportedBlockNotification, Subscribers};
+use snapshot_sync::{ImportStateSnapshotOutcome, PendingSnapshot};
+use storage_cache::StorageCache;
+use tree_route::ImportedBlocksTree;
+
+use crate::{
+    babe, block, block_import, database, executor, grandpa, header, trie::calculate_root,
+};
 
 use alloc::{collections::BTreeMap, sync::Arc};
 use core::pin::Pin;
 use futures::{
     channel::{mpsc, oneshot},
     prelude::*,
+    stream,
 };
-use parity_scale_codec::Encode as _;
 use parking_lot::Mutex;
 
 /// Message that can be sent to the block import task by the other parts of the code.
@@ -26,6 +61,35 @@ pub enum ToBlockImport {
         /// Channel where to send back the answer.
         send_back: oneshot::Sender<u64>,
     },
+    /// Ask the block import task for the current depth of each stage of the verification
+    /// pipeline.
+    QueueInfo {
+        /// Channel where to send back the answer.
+        send_back: oneshot::Sender<QueueStatus>,
+    },
+    /// Ask the block import task what the finalized block number is.
+    FinalizedBlockNumber {
+        /// Channel where to send back the answer.
+        send_back: oneshot::Sender<u64>,
+    },
+    /// Subscribe to a stream of [`ImportedBlockNotification`]s, sent after every block
+    /// successfully written to the database from now on.
+    SubscribeImported {
+        /// Channel where to send back the receiving end of the notification stream.
+        send_back: oneshot::Sender<mpsc::Receiver<ImportedBlockNotification>>,
+    },
+    /// Verify a GRANDPA justification finalizing `block_hash`, and if it checks out, advance the
+    /// finalized block to it.
+    ImportJustification {
+        /// Hash of the block the justification claims to finalize. Must be a descendant of the
+        /// current finalized block and be tracked by the task (either currently part of the best
+        /// chain, or a side-chain import that is still recent).
+        block_hash: [u8; 32],
+        /// SCALE-encoded GRANDPA justification.
+        scale_encoded_justification: Vec<u8>,
+        /// Channel where to send back the outcome.
+        send_back: oneshot::Sender<Result<(), ImportError>>,
+    },
     /// Verify the correctness of a block and apply it on the storage.
     Import {
         /// Header of the block to try to import.
@@ -35,6 +99,22 @@ pub enum ToBlockImport {
         /// Channel where to send back the outcome of the execution.
         send_back: oneshot::Sender<Result<ImportSuccess, ImportError>>,
     },
+    /// Import one chunk of a snapshot of the top trie of `block_header`, bootstrapping the
+    /// database to that block without replaying any of the blocks before it.
+    ///
+    /// Can be called repeatedly with chunks covering the same `block_header`; entries are merged
+    /// into whatever has already been received. Set `is_last_chunk` on the final call so that the
+    /// accumulated state can be checked against `block_header`'s `state_root` and installed.
+    ImportStateSnapshot {
+        /// SCALE-encoded header of the block the snapshot is for.
+        block_header: Vec<u8>,
+        /// Top-trie key/value pairs making up this chunk.
+        trie_chunk: Vec<(Vec<u8>, Vec<u8>)>,
+        /// Whether this is the last chunk of the snapshot.
+        is_last_chunk: bool,
+        /// Channel where to send back the outcome.
+        send_back: oneshot::Sender<Result<ImportStateSnapshotOutcome, ImportError>>,
+    },
 }
 
 pub struct ImportSuccess {
@@ -46,6 +126,15 @@ pub struct ImportSuccess {
     /// List of keys that have appeared, disappeared, or whose value has been modified during the
     /// execution of the block.
     pub modified_keys: Vec<Vec<u8>>,
+    /// Hashes of the blocks, if any, that used to be part of the best chain and have been
+    /// retracted in favour of the chain this block is part of, ordered from the block that used
+    /// to be the best block down to (but excluding) the common ancestor.
+    pub retracted: Vec<[u8; 32]>,
+    /// Hashes of the blocks, if any, that have been enacted on top of the common ancestor in
+    /// order to make this block part of the best chain, ordered from right after the common
+    /// ancestor up to (and including) this block. Empty if this block was imported but didn't
+    /// become the best block.
+    pub enacted: Vec<[u8; 32]>,
 }
 
 /// Error that can happen when importing a block.
@@ -53,14 +142,49 @@ pub struct ImportSuccess {
 pub enum ImportError {
     /// Error while decoding header.
     InvalidHeader(header::Error),
-    /// The parent of the block isn't the current best block.
+    /// The parent of the block is neither the current best block, the root of the
+    /// recently-imported blocks tree, nor tracked by that tree. The block is a dangling
+    /// side-chain import and is rejected; the database is not consulted, so a parent known only
+    /// to the database is also rejected with this error.
+    #[display(fmt = "The parent of the block ({:?}) could not be found.", parent_hash)]
+    ParentNotFound {
+        /// Hash of the parent that couldn't be found.
+        parent_hash: [u8; 32],
+    },
+    /// The parent of the block isn't the current best block anymore. Happens when something
+    /// else raced with this import and changed the database's best block in the meantime.
     #[display(fmt = "The parent of the block isn't the current best block.")]
     ParentIsntBest {
         /// Hash of the current best block.
         current_best_hash: [u8; 32],
     },
+    /// The unverified queue is already full. Try again later.
+    #[display(fmt = "The block import queue is full.")]
+    QueueFull,
     /// The block verification has failed. The block is invalid and should be thrown away.
     VerificationFailed(block_import::Error),
+    /// The state obtained after applying the last chunk of a snapshot doesn't match the `state_root`
+    /// announced by the snapshot's target header. The snapshot is discarded.
+    #[display(fmt = "State snapshot doesn't match the target block's state root.")]
+    SnapshotRootMismatch,
+    /// The snapshot being received grew past [`Config::max_snapshot_bytes`] before a final chunk
+    /// was ever sent. The snapshot is discarded; the sender should restart it, in smaller chunks
+    /// or against a different, presumably misbehaving, peer.
+    #[display(fmt = "State snapshot exceeded the maximum allowed size.")]
+    SnapshotTooLarge,
+    /// The target of a justification isn't tracked by the task (neither the best chain, a known
+    /// side-chain, nor the database).
+    #[display(fmt = "The target of the justification could not be found.")]
+    JustificationTargetNotFound,
+    /// The target of a justification was found, but isn't actually a descendant of the currently
+    /// finalized block: either it's a strict ancestor of it (a stale justification), or it
+    /// belongs to a side-chain that forked off before the finalized height. A justification for
+    /// the finalized block itself doesn't produce this error: it is verified as usual and, if
+    /// valid, succeeds without moving the finalized block.
+    #[display(fmt = "The target of the justification isn't a descendant of the finalized block.")]
+    JustificationTargetNotDescendant,
+    /// The justification itself failed verification.
+    InvalidJustification(finality::JustificationError),
 }
 
 /// Configuration for that task.
@@ -68,41 +192,47 @@ pub struct Config {
     /// Database where to import blocks to.
     pub database: Arc<database::Database>,
     /// Configuration for BABE, retreived from the genesis block.
-    pub babe_genesis_config: babe::BabeGenesisConfiguration,
+    pub babe_genesis_config: Arc<babe::BabeGenesisConfiguration>,
+    /// GRANDPA authorities in effect at genesis, used to bootstrap [`finality::AuthoritySet`]
+    /// tracking if the database doesn't already have a more recent authority set stored.
+    pub grandpa_genesis_authorities: Vec<grandpa::Authority>,
     /// How to spawn other background tasks.
     pub tasks_executor: Box<dyn Fn(Pin<Box<dyn Future<Output = ()> + Send>>) + Send>,
     /// Receiver for messages that the executor task will process.
     pub to_block_import: mpsc::Receiver<ToBlockImport>,
+    /// Number of blocks that can be verified concurrently by background workers.
+    pub verification_worker_count: usize,
+    /// Maximum number of blocks allowed to sit in the unverified queue before further imports are
+    /// rejected with [`ImportError::QueueFull`].
+    pub max_unverified_queue_len: usize,
+    /// Approximate maximum number of bytes of top-trie key/value pairs kept resident by the
+    /// storage cache. Not a hard limit: the byte count is derived from key and value lengths only,
+    /// ignoring the cache's own bookkeeping overhead.
+    pub storage_cache_byte_budget: usize,
+    /// Maximum number of bytes of top-trie key/value pairs a single in-progress
+    /// [`ToBlockImport::ImportStateSnapshot`] is allowed to accumulate before it is discarded with
+    /// [`ImportError::SnapshotTooLarge`], regardless of whether a final chunk was ever announced.
+    pub max_snapshot_bytes: usize,
 }
 
 /// Runs the task itself.
 pub async fn run_block_import_task(mut config: Config) {
-    // The `WasmBlob` object corresponding to the head of the chain. Set to `None` if the runtime
-    // code is modified.
-    // Used to avoid recompiling it every single time.
-    let mut wasm_blob_cache: Option<executor::WasmVmPrototype> = None;
-
-    // Cache used to calculate the storage trie root.
-    // This cache has to be kept up to date with the actual state of the storage.
-    // We pass this value whenever we verify a block. The verification process returns an updated
-    // version of this cache, suitable to be passed to verifying a direct child.
-    let mut top_trie_root_calculation_cache = Some(calculate_root::CalculationCache::empty());
-
-    // Cache of the storage at the head of the chain.
-    let mut local_storage_cache = {
-        let mut cache = BTreeMap::<Vec<u8>, Vec<u8>>::new();
-        let best_block = config.database.best_block_hash().unwrap();
-        let storage_keys = config.database.storage_top_trie_keys(best_block).unwrap();
-        for key in storage_keys {
-            let value = config
-                .database
-                .storage_top_trie_get(best_block, &key)
-                .unwrap()
-                .unwrap();
-            cache.insert(key.to_vec(), value.to_vec());
-        }
-        cache
-    };
+    // Caches of the runtime `WasmVmPrototype` and trie-root-calculation state, keyed by the hash
+    // of the block they were produced for. Reused only when the next block dispatched for
+    // verification happens to be a direct child of that exact block; dropped otherwise (typically
+    // when several competing forks are being verified concurrently).
+    let mut wasm_blob_cache: Option<([u8; 32], executor::WasmVmPrototype)> = None;
+    let mut top_trie_root_calculation_cache: Option<([u8; 32], calculate_root::CalculationCache)> =
+        None;
+
+    // Bounded cache of recently read or written top-trie entries, as of the database's current
+    // best block. Unlike loading the whole top trie upfront, this scales independently of the
+    // size of the chain's state; anything not resident is read from `config.database` on demand
+    // (see `storage_cache::resolve` and friends, used both below and by verification workers).
+    // Wrapped in a mutex because workers verifying blocks concurrently share it.
+    let storage_cache = Arc::new(Mutex::new(StorageCache::new(
+        config.storage_cache_byte_budget,
+    )));
 
     // Because we store blocks in the database asynchronously, we must make sure that each
     // database import starts after the previous block has finished being imported.
@@ -122,107 +252,343 @@ pub async fn run_block_import_task(mut config: Config) {
         .unwrap()
         .to_vec();
 
-    // Main loop of the task. Processes received messages.
-    while let Some(event) = config.to_block_import.next().await {
+    // Root that `imported_blocks_tree` is built on top of: the best block at the time the task
+    // was started. Everything above it that has been imported since, whether part of the best
+    // chain or not, is tracked by the tree so that tree routes can be computed between any two of
+    // these blocks.
+    //
+    // Installing a state snapshot (see `ToBlockImport::ImportStateSnapshot` below) moves this
+    // root forward to the snapshot's block, since the tree has no way to compute a route to or
+    // from any block that came before it. Advancing finality (see
+    // `ToBlockImport::ImportJustification` below) moves it forward too, to the newly-finalized
+    // block: `ImportedBlocksTree::prune_finalized` drops every tracked node at or below that
+    // block other than the block itself, including any that used to sit between this root and
+    // it, so the root has to move in lockstep or `route` would no longer be able to walk back to
+    // it.
+    let mut tree_root = best_block_hash;
+    let mut tree_root_header = best_block_header.clone();
+    let mut imported_blocks_tree = ImportedBlocksTree::empty();
+
+    // Cumulative weight of the current best chain, used to decide whether a competing branch
+    // should overtake it. We don't track BABE primary-slot counts yet, so the block number is
+    // used as a proxy; this is only correct as long as all chains being compared have the same
+    // per-block weight.
+    // TODO: use the actual BABE primary-slot count once available
+    let mut best_block_weight = header::decode(&best_block_header).unwrap().number;
+
+    // The finalized block is guaranteed, by GRANDPA, to never be reverted: reorgs are not allowed
+    // to retract it or any of its ancestors.
+    let mut finalized_block_hash = config.database.finalized_block_hash().unwrap();
+    let mut finalized_block_number = header::decode(
+        &config
+            .database
+            .block_scale_encoded_header(&finalized_block_hash)
+            .unwrap()
+            .unwrap(),
+    )
+    .unwrap()
+    .number;
+
+    // Authorities that justifications are checked against; kept up to date by scanning the
+    // consensus digest of every header between the previous and new finalized block.
+    let mut current_authority_set = config.database.grandpa_authority_set().unwrap_or_else(|| {
+        AuthoritySet {
+            set_id: 0,
+            authorities: config.grandpa_genesis_authorities.clone(),
+        }
+    });
+
+    // Blocks accepted but not yet picked up by a worker, and the counter of workers currently
+    // busy, bounding the two pipeline stages described in the module documentation.
+    let mut unverified_queue = UnverifiedQueue::new(config.max_unverified_queue_len);
+    let verifying_counter = VerifyingCounter::new();
+
+    // Subscribers to import notifications. Wrapped in a mutex because the best-chain commit
+    // happens in a separately-spawned database-writing task (see below) that also needs to push
+    // to it once its write completes.
+    let subscribers = Arc::new(Mutex::new(Subscribers::new()));
+
+    // If a previous run of the task was interrupted partway through receiving a state snapshot,
+    // pick up where it left off rather than asking the sender to start over.
+    let mut snapshot_sync_state: Option<PendingSnapshot> =
+        config.database.pending_snapshot().map(|(header, entries)| {
+            let mut snapshot = PendingSnapshot::new(header);
+            snapshot.extend(entries);
+            snapshot
+        });
+
+    // Channel through which background workers report a block's verification outcome back to
+    // this loop, which performs the actual (serialized) commit.
+    let (verified_tx, verified_rx) = mpsc::unbounded::<VerifiedImport>();
+
+    enum Event {
+        Incoming(ToBlockImport),
+        Verified(VerifiedImport),
+    }
+
+    let mut events = stream::select(
+        (&mut config.to_block_import).map(Event::Incoming),
+        verified_rx.map(Event::Verified),
+    );
+
+    // Main loop of the task. Processes received messages and verification outcomes as they come.
+    while let Some(event) = events.next().await {
         match event {
-            ToBlockImport::BestBlockNumber { send_back } => {
+            Event::Incoming(ToBlockImport::BestBlockNumber { send_back }) => {
                 let _ = send_back.send(header::decode(&best_block_header).unwrap().number);
             }
 
-            ToBlockImport::Import {
-                scale_encoded_header,
-                body,
+            Event::Incoming(ToBlockImport::QueueInfo { send_back }) => {
+                let _ = send_back.send(QueueStatus {
+                    unverified: unverified_queue.len(),
+                    verifying: verifying_counter.get(),
+                    // A block is committed in the very same loop iteration that receives its
+                    // verification outcome, so nothing ever actually waits in a "verified" stage.
+                    verified: 0,
+                });
+            }
+
+            Event::Incoming(ToBlockImport::FinalizedBlockNumber { send_back }) => {
+                let _ = send_back.send(finalized_block_number);
+            }
+
+            Event::Incoming(ToBlockImport::SubscribeImported { send_back }) => {
+                let _ = send_back.send(subscribers.lock().subscribe());
+            }
+
+            Event::Incoming(ToBlockImport::ImportJustification {
+                block_hash,
+                scale_encoded_justification,
                 send_back,
-            } => {
-                let decoded_header = match header::decode(&scale_encoded_header) {
-                    Ok(h) => h,
-                    Err(err) => {
-                        let _ = send_back.send(Err(ImportError::InvalidHeader(err)));
-                        return;
+            }) => {
+                // Looks up the header of a block that is the current best, a known side-chain, or
+                // the tree root, falling back to the database for anything older.
+                let header_for_hash = |hash: [u8; 32]| -> Option<Vec<u8>> {
+                    if hash == tree_root {
+                        Some(tree_root_header.clone())
+                    } else if let Some(node) = imported_blocks_tree.get(&hash) {
+                        Some(node.scale_encoded_header.clone())
+                    } else {
+                        config
+                            .database
+                            .block_scale_encoded_header(&hash)
+                            .unwrap()
+                            .map(|header| header.to_vec())
                     }
                 };
 
-                // We only accept blocks whose parent is the current best block.
-                if best_block_hash != *decoded_header.parent_hash {
-                    let _ = send_back.send(Err(ImportError::ParentIsntBest {
-                        current_best_hash: best_block_hash,
-                    }));
+                let target_header = match header_for_hash(block_hash) {
+                    Some(header) => header,
+                    None => {
+                        let _ = send_back.send(Err(ImportError::JustificationTargetNotFound));
+                        continue;
+                    }
+                };
+                let target_number = header::decode(&target_header).unwrap().number;
+
+                if let Err(err) = finality::verify_justification(
+                    &scale_encoded_justification,
+                    block_hash,
+                    target_number,
+                    &current_authority_set,
+                ) {
+                    let _ = send_back.send(Err(ImportError::InvalidJustification(err)));
                     continue;
                 }
 
-                // In order to avoid parsing/compiling the runtime code every single time, we
-                // maintain a cache of the `WasmBlob` of the head of the chain.
-                let runtime_wasm_blob = if let Some(vm) = wasm_blob_cache.take() {
-                    vm
-                } else {
-                    let code = local_storage_cache.get(&b":code"[..]).unwrap();
-                    executor::WasmVmPrototype::new(&code).unwrap()
+                // Walk the canonical chain from just after the currently-finalized block up to
+                // the newly-finalized one, updating the authority set from each header's
+                // consensus digest along the way, in order. Unlike a well-behaved sender always
+                // targeting a genuine descendant of the finalized block, a stale
+                // justification (for an already-finalized ancestor, or for a side-chain block
+                // that forked before the finalized height) is ordinary network input, so the walk
+                // is bounded by `finalized_block_number` rather than assumed to always reach
+                // `finalized_block_hash`.
+                let mut chain = Vec::new();
+                let mut cursor = block_hash;
+                let mut cursor_header = target_header.clone();
+                let is_descendant = loop {
+                    if cursor == finalized_block_hash {
+                        break true;
+                    }
+                    if header::decode(&cursor_header).unwrap().number <= finalized_block_number {
+                        break false;
+                    }
+                    let parent_hash = *header::decode(&cursor_header).unwrap().parent_hash;
+                    chain.push(cursor_header);
+                    cursor = parent_hash;
+                    cursor_header = match header_for_hash(cursor) {
+                        Some(header) => header,
+                        None => break false,
+                    };
                 };
 
-                // Now perform the actual block verification.
-                // Note that this does **not** modify `local_storage_cache`.
-                let import_result = {
-                    // TODO: this mutex is stupid, the `crate::block_import` module should be reworked
-                    // to be coroutine-like
-                    let local_storage_cache = Arc::new(Mutex::new(&mut local_storage_cache));
-
-                    block_import::verify_block(block_import::Config {
-                        runtime: runtime_wasm_blob,
-                        babe_genesis_configuration: &config.babe_genesis_config,
-                        block_header: decoded_header,
-                        block_body: body.iter().map(|e| &e[..]),
-                        parent_block_header: header::decode(&best_block_header).unwrap(),
-                        parent_storage_get: {
-                            let local_storage_cache = local_storage_cache.clone();
-                            move |key: Vec<u8>| {
-                                let ret: Option<Vec<u8>> =
-                                    local_storage_cache.lock().get(&key).cloned();
-                                async move { ret }
-                            }
-                        },
-                        parent_storage_keys_prefix: {
-                            let local_storage_cache = local_storage_cache.clone();
-                            move |prefix: Vec<u8>| {
-                                let ret = local_storage_cache
-                                    .lock()
-                                    .range(prefix.clone()..)
-                                    .take_while(|(k, _)| k.starts_with(&prefix))
-                                    .map(|(k, _)| k.to_vec())
-                                    .collect();
-                                async move { ret }
-                            }
-                        },
-                        parent_storage_next_key: {
-                            let local_storage_cache = local_storage_cache.clone();
-                            move |key: Vec<u8>| {
-                                struct CustomBound(Vec<u8>);
-                                impl core::ops::RangeBounds<Vec<u8>> for CustomBound {
-                                    fn start_bound(&self) -> core::ops::Bound<&Vec<u8>> {
-                                        core::ops::Bound::Excluded(&self.0)
-                                    }
-                                    fn end_bound(&self) -> core::ops::Bound<&Vec<u8>> {
-                                        core::ops::Bound::Unbounded
-                                    }
-                                }
-                                let ret = local_storage_cache
-                                    .lock()
-                                    .range(CustomBound(key))
-                                    .next()
-                                    .map(|(k, _)| k.to_vec());
-                                async move { ret }
-                            }
-                        },
-                        top_trie_root_calculation_cache: top_trie_root_calculation_cache.take(),
-                    })
-                    .await
+                if !is_descendant {
+                    let _ = send_back.send(Err(ImportError::JustificationTargetNotDescendant));
+                    continue;
+                }
+
+                for header_bytes in chain.into_iter().rev() {
+                    current_authority_set
+                        .update_from_header_digest(&header::decode(&header_bytes).unwrap());
+                }
+                config
+                    .database
+                    .set_grandpa_authority_set(&current_authority_set);
+
+                finalized_block_hash = block_hash;
+                finalized_block_number = target_number;
+                config.database.set_finalized_block(finalized_block_hash);
+                imported_blocks_tree.prune_finalized(finalized_block_hash, finalized_block_number);
+
+                // The nodes between the old root and the block that was just finalized have
+                // just been pruned above: advance the root to match, or a later `route` call
+                // that needs to walk back that far would fail to find them.
+                tree_root = finalized_block_hash;
+                tree_root_header = target_header;
+
+                let _ = send_back.send(Ok(()));
+            }
+
+            Event::Incoming(ToBlockImport::ImportStateSnapshot {
+                block_header,
+                trie_chunk,
+                is_last_chunk,
+                send_back,
+            }) => {
+                let snapshot = match &mut snapshot_sync_state {
+                    Some(snapshot) if snapshot.scale_encoded_header == block_header => snapshot,
+                    // Either there's no snapshot in progress, or one for a different block is:
+                    // in both cases, the older, incomplete data (if any) is abandoned.
+                    _ => snapshot_sync_state.insert(PendingSnapshot::new(block_header.clone())),
                 };
 
-                // If the block verification failed, we can just discard everything as nothing
-                // has been committed yet.
-                let import_result = match import_result {
+                config
+                    .database
+                    .persist_snapshot_chunk(&block_header, trie_chunk.iter().cloned());
+                snapshot.extend(trie_chunk);
+
+                // Bound how much a sender can make us accumulate before ever sending a final
+                // chunk: without this, a peer that stalls partway through (or simply keeps
+                // sending chunks forever) could grow memory and on-disk storage without limit.
+                if snapshot.accumulated_bytes() > config.max_snapshot_bytes {
+                    config.database.discard_pending_snapshot(&block_header);
+                    snapshot_sync_state = None;
+                    let _ = send_back.send(Err(ImportError::SnapshotTooLarge));
+                    continue;
+                }
+
+                if !is_last_chunk {
+                    let _ = send_back.send(Ok(ImportStateSnapshotOutcome::Pending {
+                        received_keys: snapshot.received_keys(),
+                    }));
+                } else {
+                    let decoded_header = match header::decode(&block_header) {
+                        Ok(h) => h,
+                        Err(err) => {
+                            let _ = send_back.send(Err(ImportError::InvalidHeader(err)));
+                            snapshot_sync_state = None;
+                            continue;
+                        }
+                    };
+
+                    let computed_root = calculate_root::root_merkle_value(
+                        snapshot
+                            .accumulated
+                            .iter()
+                            .map(|(key, value)| (&key[..], &value[..])),
+                    );
+
+                    if computed_root != *decoded_header.state_root {
+                        config.database.discard_pending_snapshot(&block_header);
+                        let _ = send_back.send(Err(ImportError::SnapshotRootMismatch));
+                        snapshot_sync_state = None;
+                        continue;
+                    }
+
+                    // The root matches: install the snapshot as the new best block, replacing
+                    // the database's content wholesale rather than diffing against it.
+                    config.database.install_state_snapshot(
+                        &block_header,
+                        snapshot
+                            .accumulated
+                            .iter()
+                            .map(|(key, value)| (key.clone(), value.clone())),
+                    );
+
+                    // The previously-cached entries describe a state this node no longer has any
+                    // history for: clear them rather than risk mixing them with the freshly
+                    // installed state, and let the cache repopulate lazily from the database.
+                    storage_cache.lock().clear();
+                    let new_hash = header::hash_from_scale_encoded_header(&block_header);
+                    wasm_blob_cache = snapshot.accumulated.get(&b":code"[..]).map(|code| {
+                        (new_hash, executor::WasmVmPrototype::new(code).unwrap())
+                    });
+                    top_trie_root_calculation_cache = None;
+
+                    // Normal block import resumes from here: this snapshot becomes the new root
+                    // of the side-chain tree, since the tree has no information about anything
+                    // that came before it.
+                    best_block_hash = new_hash;
+                    best_block_header = block_header.clone();
+                    best_block_weight = decoded_header.number;
+                    tree_root = new_hash;
+                    tree_root_header = block_header;
+                    imported_blocks_tree = ImportedBlocksTree::empty();
+
+                    snapshot_sync_state = None;
+
+                    let _ = send_back.send(Ok(ImportStateSnapshotOutcome::Installed));
+                }
+            }
+
+            Event::Incoming(ToBlockImport::Import {
+                scale_encoded_header,
+                body,
+                send_back,
+            }) => match header::decode(&scale_encoded_header) {
+                Err(err) => {
+                    let _ = send_back.send(Err(ImportError::InvalidHeader(err)));
+                }
+                Ok(decoded_header) => {
+                    let parent_hash = *decoded_header.parent_hash;
+
+                    // We accept a block if its parent is the current best block, is tracked by
+                    // `imported_blocks_tree` (a side-chain or formerly-best block that is still
+                    // recent), or is the root the tree was built on top of. Anything else is a
+                    // dangling import and is rejected outright, without ever touching the queue.
+                    if parent_hash != best_block_hash
+                        && parent_hash != tree_root
+                        && !imported_blocks_tree.contains(&parent_hash)
+                    {
+                        let _ = send_back.send(Err(ImportError::ParentNotFound { parent_hash }));
+                    } else {
+                        let pending = PendingImport {
+                            scale_encoded_header,
+                            body,
+                            parent_hash,
+                            send_back,
+                        };
+                        if let Err(pending) = unverified_queue.push(pending) {
+                            let _ = pending.send_back.send(Err(ImportError::QueueFull));
+                        }
+                    }
+                }
+            },
+
+            Event::Verified(VerifiedImport {
+                scale_encoded_header,
+                body,
+                parent_hash,
+                result,
+                parent_storage_overlay,
+                send_back,
+            }) => {
+                verifying_counter.decrement();
+
+                let import_result = match result {
                     Ok(r) => r,
                     Err(err) => {
-                        assert!(top_trie_root_calculation_cache.is_none());
                         let _ = send_back.send(Err(ImportError::VerificationFailed(err)));
                         continue;
                     }
@@ -231,83 +597,387 @@ pub async fn run_block_import_task(mut config: Config) {
                 // The block is correct. The import is going to be successful. 🎉
                 // TODO: ^ unless something else wrote in the DB in the meanwhile
 
-                // We now update the local values for the next iteration.
-                // Put back the same runtime `wasm_blob_cache` unless changes have been made
-                // to `:code`.
-                top_trie_root_calculation_cache =
-                    Some(import_result.top_trie_root_calculation_cache);
+                let block_number = header::decode(&scale_encoded_header).unwrap().number;
+                let new_hash = header::hash_from_scale_encoded_header(&scale_encoded_header);
+
+                // Values immediately prior to this block's changes, so that its effect on storage
+                // can be undone if it is ever retracted.
+                let storage_top_trie_previous_values: BTreeMap<Vec<u8>, Option<Vec<u8>>> =
+                    import_result
+                        .storage_top_trie_changes
+                        .keys()
+                        .map(|key| {
+                            let previous_value = storage_cache::resolve(
+                                key,
+                                &parent_storage_overlay,
+                                &storage_cache,
+                                &config.database,
+                            );
+                            (key.clone(), previous_value)
+                        })
+                        .collect();
+
+                imported_blocks_tree.insert(
+                    new_hash,
+                    scale_encoded_header.clone(),
+                    parent_hash,
+                    block_number,
+                    block_number,
+                    import_result.storage_top_trie_changes.clone(),
+                    storage_top_trie_previous_values,
+                );
+
                 if !import_result
                     .storage_top_trie_changes
                     .contains_key(&b":code"[..])
                 {
-                    wasm_blob_cache = Some(import_result.parent_runtime);
-                }
-                for (key, value) in &import_result.storage_top_trie_changes {
-                    if let Some(value) = value {
-                        local_storage_cache.insert(key.clone(), value.clone());
-                    } else {
-                        local_storage_cache.remove(key);
-                    }
+                    wasm_blob_cache = Some((new_hash, import_result.parent_runtime));
                 }
+                top_trie_root_calculation_cache =
+                    Some((new_hash, import_result.top_trie_root_calculation_cache));
 
-                let current_best_hash = best_block_hash.clone();
-                best_block_hash = header::hash_from_scale_encoded_header(&scale_encoded_header);
-                best_block_header = scale_encoded_header.clone();
+                // Weight of the chain ending with this block, used (rather than the raw block
+                // number) to decide whether it should overtake the current best chain: see
+                // `TreeNode::cumulative_weight`'s doc comment for why this matters once BABE
+                // primary-slot counts are taken into account.
+                let weight = imported_blocks_tree.cumulative_weight(&new_hash);
 
-                // Now spawn a database task dedicated entirely to writing the block.
-                (config.tasks_executor)({
-                    let best_block_hash = best_block_hash.clone();
-                    let database = config.database.clone();
-                    let storage_top_trie_changes = import_result.storage_top_trie_changes;
+                // A branch that would retract a finalized block can never become best, no matter
+                // how heavy it is: GRANDPA finality guarantees that block can never be reverted.
+                // Such a branch is simply kept as a non-best import, like any other losing fork.
+                let would_violate_finality = weight > best_block_weight && {
+                    let route = imported_blocks_tree
+                        .route(best_block_hash, new_hash, tree_root)
+                        .expect(
+                            "both endpoints were just inserted into, or are the root of, the tree",
+                        );
+                    route.retracted.iter().any(|retracted| {
+                        imported_blocks_tree
+                            .get(retracted)
+                            .map_or(false, |node| node.number <= finalized_block_number)
+                    })
+                };
 
-                    let previous_block_db_import = previous_block_database_import_finished.take();
-                    let (finished_tx, finished_rx) = oneshot::channel();
-                    previous_block_database_import_finished = Some(finished_rx);
+                if weight > best_block_weight && !would_violate_finality {
+                    // This branch is now heavier than the current best chain: reorganize onto it.
+                    // A simple linear extension of the current best chain is just the special
+                    // case where `route.retracted` is empty and `route.enacted` is `[new_hash]`.
+                    let route = imported_blocks_tree
+                        .route(best_block_hash, new_hash, tree_root)
+                        .expect(
+                            "both endpoints were just inserted into, or are the root of, the tree",
+                        );
 
-                    Box::pin(async move {
-                        if let Some(previous_block_db_import) = previous_block_db_import {
-                            let _ = previous_block_db_import.await;
+                    // Figure out what the route's diffs would mean for the storage cache, but
+                    // don't apply them yet: `storage_cache` is documented to only ever hold
+                    // values as of the database's current best block, and the database write
+                    // below hasn't happened yet (it's about to be handed off to a separately
+                    // spawned, asynchronous task). Applying the diffs here would let the cache
+                    // race ahead of the database; if one of these entries were then evicted
+                    // before the write below actually completed, the next cache miss would fall
+                    // through to `database.best_block_hash()`, which would still be behind, and
+                    // silently re-cache a stale value as if it were current. Collected into a map
+                    // so that, like the sequential `insert`/`remove` calls below would, a key
+                    // touched by both a retraction and an enactment ends up with the enacted
+                    // (later) value.
+                    let mut cache_updates: BTreeMap<Vec<u8>, Option<Vec<u8>>> = BTreeMap::new();
+                    for retracted in &route.retracted {
+                        let node = imported_blocks_tree.get(retracted).unwrap();
+                        for (key, old_value) in &node.storage_top_trie_previous_values {
+                            cache_updates.insert(key.clone(), old_value.clone());
                         }
+                    }
+                    for enacted in &route.enacted {
+                        let node = imported_blocks_tree.get(enacted).unwrap();
+                        for (key, new_value) in &node.storage_top_trie_changes {
+                            cache_updates.insert(key.clone(), new_value.clone());
+                        }
+                    }
+
+                    let current_best_hash = best_block_hash;
+                    best_block_hash = new_hash;
+                    best_block_header = scale_encoded_header.clone();
+                    best_block_weight = weight;
+
+                    // Now spawn a database task dedicated entirely to writing the block.
+                    (config.tasks_executor)({
+                        let database = config.database.clone();
+                        let subscribers = subscribers.clone();
+                        let storage_cache = storage_cache.clone();
+                        let storage_top_trie_changes = import_result.storage_top_trie_changes;
+                        let retracted = route.retracted.clone();
+                        let enacted = route.enacted.clone();
+
+                        let previous_block_db_import =
+                            previous_block_database_import_finished.take();
+                        let (finished_tx, finished_rx) = oneshot::channel();
+                        previous_block_database_import_finished = Some(finished_rx);
+
+                        Box::pin(async move {
+                            if let Some(previous_block_db_import) = previous_block_db_import {
+                                let _ = previous_block_db_import.await;
+                            }
+
+                            let db_import_result = database.insert_new_best(
+                                current_best_hash,
+                                &scale_encoded_header,
+                                body.iter().cloned(),
+                                // TODO: we can't use `into_iter()` because the `Clone` trait isn't implemented; should be fixed in hashbrown
+                                storage_top_trie_changes
+                                    .iter()
+                                    .map(|(k, v)| (k.clone(), v.clone())),
+                            );
+
+                            match db_import_result {
+                                Ok(()) => {}
+                                Err(database::InsertNewBestError::ObsoleteCurrentHead) => {
+                                    // TODO: look into the implications for the parent task
+                                    // `current_best_hash` is the task's own view of the best block at the
+                                    // time this write was scheduled. The import can still fail if something
+                                    // else has modified the database's best block while we were busy
+                                    // verifying the block.
+                                    let current_best_hash = database.best_block_hash().unwrap();
+                                    let _ = send_back.send(Err(ImportError::ParentIsntBest {
+                                        current_best_hash,
+                                    }));
+                                    return;
+                                }
+                                Err(database::InsertNewBestError::Access(err)) => {
+                                    panic!("Database internal error: {}", err);
+                                }
+                            }
 
-                        let db_import_result = database.insert_new_best(
-                            current_best_hash,
+                            // Block has been successfully imported! 🎉
+                            // Only now that the database genuinely reflects it is it safe to
+                            // apply the cache diffs computed above.
+                            {
+                                let mut storage_cache = storage_cache.lock();
+                                for (key, value) in &cache_updates {
+                                    match value {
+                                        Some(value) => {
+                                            storage_cache.insert(key.clone(), value.clone())
+                                        }
+                                        None => storage_cache.remove(key),
+                                    };
+                                }
+                            }
+
+                            let modified_keys: Vec<_> =
+                                storage_top_trie_changes.keys().cloned().collect();
+
+                            subscribers.lock().notify(ImportedBlockNotification {
+                                hash: new_hash,
+                                number: block_number,
+                                scale_encoded_header: scale_encoded_header.clone(),
+                                is_new_best: true,
+                                modified_keys: modified_keys.clone(),
+                                retracted: retracted.clone(),
+                                enacted: enacted.clone(),
+                            });
+
+                            let _ = send_back.send(Ok(ImportSuccess {
+                                scale_encoded_header,
+                                body,
+                                modified_keys,
+                                retracted,
+                                enacted,
+                            }));
+
+                            let _ = finished_tx.send(());
+                        })
+                    });
+                } else {
+                    // Side-chain import: store the block without touching the best chain.
+                    config
+                        .database
+                        .insert_non_best_block(
                             &scale_encoded_header,
                             body.iter().cloned(),
-                            // TODO: we can't use `into_iter()` because the `Clone` trait isn't implemented; should be fixed in hashbrown
-                            storage_top_trie_changes
+                            import_result
+                                .storage_top_trie_changes
                                 .iter()
                                 .map(|(k, v)| (k.clone(), v.clone())),
-                        );
+                        )
+                        .unwrap();
 
-                        match db_import_result {
-                            Ok(()) => {}
-                            Err(database::InsertNewBestError::ObsoleteCurrentHead) => {
-                                // TODO: look into the implications for the parent task
-                                // We have already checked above whether the parent of the block to import
-                                // was indeed the best block in the database. However the import can still
-                                // fail if something else has modified the database's best block while we
-                                // were busy verifying the block.
-                                let current_best_hash = database.best_block_hash().unwrap();
-                                let _ = send_back
-                                    .send(Err(ImportError::ParentIsntBest { current_best_hash }));
-                                return;
-                            }
-                            Err(database::InsertNewBestError::Access(err)) => {
-                                panic!("Database internal error: {}", err);
-                            }
+                    let modified_keys: Vec<_> = import_result
+                        .storage_top_trie_changes
+                        .keys()
+                        .cloned()
+                        .collect();
+
+                    subscribers.lock().notify(ImportedBlockNotification {
+                        hash: new_hash,
+                        number: block_number,
+                        scale_encoded_header: scale_encoded_header.clone(),
+                        is_new_best: false,
+                        modified_keys: modified_keys.clone(),
+                        retracted: Vec::new(),
+                        enacted: Vec::new(),
+                    });
+
+                    let _ = send_back.send(Ok(ImportSuccess {
+                        scale_encoded_header,
+                        body,
+                        modified_keys,
+                        retracted: Vec::new(),
+                        enacted: Vec::new(),
+                    }));
+                }
+            }
+        }
+
+        // Either a new block was just queued, or a worker just freed up (and the tree might have
+        // gained a block whose children were waiting on it): try to keep the worker pool full.
+        while verifying_counter.get() < config.verification_worker_count {
+            let ready = unverified_queue.pop_ready(|parent_hash| {
+                *parent_hash == best_block_hash
+                    || *parent_hash == tree_root
+                    || imported_blocks_tree.contains(parent_hash)
+            });
+            let pending = match ready {
+                Some(pending) => pending,
+                None => break,
+            };
+
+            // Rebuild the parent's storage overlay by replaying the tree route from the current
+            // best chain: only the keys touched along the route need an entry here, since
+            // everything else falls through to the storage cache/database (see
+            // `storage_cache::resolve`) exactly as it would for the current best block.
+            let route = imported_blocks_tree
+                .route(best_block_hash, pending.parent_hash, tree_root)
+                .expect(
+                    "pending.parent_hash was checked to be tracked by the tree, or to be the tree root, \
+                     by pop_ready's predicate",
+                );
+
+            let mut verification_overlay: BTreeMap<Vec<u8>, Option<Vec<u8>>> = BTreeMap::new();
+            for retracted in &route.retracted {
+                let node = imported_blocks_tree.get(retracted).unwrap();
+                for (key, old_value) in &node.storage_top_trie_previous_values {
+                    verification_overlay.insert(key.clone(), old_value.clone());
+                }
+            }
+            for enacted in &route.enacted {
+                let node = imported_blocks_tree.get(enacted).unwrap();
+                for (key, new_value) in &node.storage_top_trie_changes {
+                    verification_overlay.insert(key.clone(), new_value.clone());
+                }
+            }
+
+            let parent_scale_encoded_header = if pending.parent_hash == tree_root {
+                tree_root_header.clone()
+            } else if pending.parent_hash == best_block_hash {
+                best_block_header.clone()
+            } else {
+                imported_blocks_tree
+                    .get(&pending.parent_hash)
+                    .unwrap()
+                    .scale_encoded_header
+                    .clone()
+            };
+
+            let runtime_wasm_blob = match wasm_blob_cache.take() {
+                Some((hash, vm)) if hash == pending.parent_hash => vm,
+                _ => {
+                    let code = storage_cache::resolve(
+                        &b":code"[..],
+                        &verification_overlay,
+                        &storage_cache,
+                        &config.database,
+                    )
+                    .unwrap();
+                    executor::WasmVmPrototype::new(&code).unwrap()
+                }
+            };
+
+            let root_calculation_cache = match top_trie_root_calculation_cache.take() {
+                Some((hash, cache)) if hash == pending.parent_hash => cache,
+                _ => calculate_root::CalculationCache::empty(),
+            };
+
+            verifying_counter.increment();
+
+            let PendingImport {
+                scale_encoded_header,
+                body,
+                parent_hash,
+                send_back,
+            } = pending;
+            let babe_genesis_config = config.babe_genesis_config.clone();
+            let verified_tx = verified_tx.clone();
+            // The overlay is only ever read by these closures (the route's diffs are already
+            // computed), so an `Arc` without a further lock is enough, unlike `storage_cache`
+            // which is shared, mutable, point-lookup state.
+            let verification_overlay = Arc::new(verification_overlay);
+            let storage_cache = storage_cache.clone();
+            let database = config.database.clone();
+
+            (config.tasks_executor)(Box::pin(async move {
+                let result = block_import::verify_block(block_import::Config {
+                    runtime: runtime_wasm_blob,
+                    babe_genesis_configuration: &babe_genesis_config,
+                    block_header: header::decode(&scale_encoded_header).unwrap(),
+                    block_body: body.iter().map(|e| &e[..]),
+                    parent_block_header: header::decode(&parent_scale_encoded_header).unwrap(),
+                    parent_storage_get: {
+                        let verification_overlay = verification_overlay.clone();
+                        let storage_cache = storage_cache.clone();
+                        let database = database.clone();
+                        move |key: Vec<u8>| {
+                            let ret = storage_cache::resolve(
+                                &key,
+                                &verification_overlay,
+                                &storage_cache,
+                                &database,
+                            );
+                            async move { ret }
+                        }
+                    },
+                    parent_storage_keys_prefix: {
+                        let verification_overlay = verification_overlay.clone();
+                        let database = database.clone();
+                        move |prefix: Vec<u8>| {
+                            let ret = storage_cache::keys_prefix(
+                                &prefix,
+                                &verification_overlay,
+                                &database,
+                            );
+                            async move { ret }
+                        }
+                    },
+                    parent_storage_next_key: {
+                        let verification_overlay = verification_overlay.clone();
+                        let database = database.clone();
+                        move |key: Vec<u8>| {
+                            let ret =
+                                storage_cache::next_key(&key, &verification_overlay, &database);
+                            async move { ret }
                         }
+                    },
+                    top_trie_root_calculation_cache: Some(root_calculation_cache),
+                })
+                .await;
 
-                        // Block has been successfully imported! 🎉
-                        let _ = send_back.send(Ok(ImportSuccess {
-                            scale_encoded_header,
-                            body,
-                            modified_keys: storage_top_trie_changes.keys().cloned().collect(),
-                        }));
+                // Verification never mutates `verification_overlay`, and all the closures above
+                // are dropped together with the `block_import::Config` they were moved into once
+                // `verify_block` resolves, so we're the only owner left.
+                let parent_storage_overlay = match Arc::try_unwrap(verification_overlay) {
+                    Ok(overlay) => overlay,
+                    Err(_) => unreachable!(
+                        "verify_block doesn't retain the storage closures past its own return"
+                    ),
+                };
 
-                        let _ = finished_tx.send(());
-                    })
+                let _ = verified_tx.unbounded_send(VerifiedImport {
+                    scale_encoded_header,
+                    body,
+                    parent_hash,
+                    result,
+                    parent_storage_overlay,
+                    send_back,
                 });
-            }
+            }));
         }
     }
 }
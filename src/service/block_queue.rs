@@ -0,0 +1,212 @@
+//! Queue of blocks waiting to be verified, decoupling their reception from their verification.
+//!
+//! [`run_block_import_task`](super::block_import_task::run_block_import_task) pushes every
+//! accepted [`ToBlockImport::Import`](super::block_import_task::ToBlockImport::Import) message
+//! onto an [`UnverifiedQueue`] instead of verifying it inline. A bounded pool of worker tasks
+//! (size set by [`crate::service::block_import_task::Config::verification_worker_count`]) then
+//! pull ready blocks off that queue, run the CPU-bound WASM verification concurrently, and send
+//! the outcome back to the main loop through a [`VerifiedImport`], which still commits blocks to
+//! the database one at a time, in parent order.
+//!
+//! A block is only "ready" to be picked up once its parent has itself already been verified
+//! (tracked by [`crate::service::tree_route::ImportedBlocksTree`], or is the current best block),
+//! because verification needs the parent's post-execution storage. This means that a run of
+//! blocks belonging to the same chain is still verified one at a time — only unrelated blocks
+//! (typically competing forks) actually run concurrently. Pipelining still pays off because it
+//! lets the task keep accepting and queueing new blocks while a previous one is being verified,
+//! instead of blocking on it.
+
+use crate::block_import;
+
+use alloc::{collections::BTreeMap, collections::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use futures::channel::oneshot;
+
+/// A block that has been accepted but is still waiting for a worker to verify it.
+pub struct PendingImport {
+    /// Header of the block to verify.
+    pub scale_encoded_header: Vec<u8>,
+    /// Body of the block to verify.
+    pub body: Vec<Vec<u8>>,
+    /// Hash of the parent of the block, extracted from the header ahead of time so that the queue
+    /// doesn't need to re-decode it.
+    pub parent_hash: [u8; 32],
+    /// Channel where to send back the outcome of the import, once known.
+    pub send_back: oneshot::Sender<
+        Result<
+            super::block_import_task::ImportSuccess,
+            super::block_import_task::ImportError,
+        >,
+    >,
+}
+
+/// Outcome of a worker having verified a [`PendingImport`].
+pub struct VerifiedImport {
+    /// Header of the block that was verified.
+    pub scale_encoded_header: Vec<u8>,
+    /// Body of the block that was verified.
+    pub body: Vec<Vec<u8>>,
+    /// Hash of the parent of the block.
+    pub parent_hash: [u8; 32],
+    /// Outcome of the verification itself.
+    pub result: Result<block_import::Success, block_import::Error>,
+    /// The tree-route diffs that were layered on top of the storage cache/database while
+    /// verifying this block (see `crate::service::storage_cache::resolve`): for every key they
+    /// touched, its value immediately before this block (`None` if the key didn't exist). Handed
+    /// back to the commit stage so it doesn't need to replay the route a second time to compute
+    /// this block's own `storage_top_trie_previous_values`.
+    pub parent_storage_overlay: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Channel where to send back the outcome of the import, once the commit stage is done.
+    pub send_back: oneshot::Sender<
+        Result<
+            super::block_import_task::ImportSuccess,
+            super::block_import_task::ImportError,
+        >,
+    >,
+}
+
+/// Depth of each stage of the pipeline, reported in response to
+/// [`ToBlockImport::QueueInfo`](super::block_import_task::ToBlockImport::QueueInfo).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueueStatus {
+    /// Number of blocks that have been received but not yet picked up by a worker.
+    pub unverified: usize,
+    /// Number of blocks currently being verified by a worker.
+    pub verifying: usize,
+    /// Number of blocks that have finished verification and are waiting for the main loop to
+    /// commit them.
+    pub verified: usize,
+}
+
+/// FIFO queue of blocks that have been accepted but not yet dispatched to a worker.
+///
+/// Bounded by a capacity so that a burst of incoming blocks applies backpressure on the sender
+/// rather than growing the queue (and the memory it uses) without limit.
+#[derive(Default)]
+pub struct UnverifiedQueue {
+    queue: VecDeque<PendingImport>,
+    capacity: usize,
+}
+
+impl UnverifiedQueue {
+    /// Creates a new empty queue that refuses pushes once it holds `capacity` blocks.
+    pub fn new(capacity: usize) -> Self {
+        UnverifiedQueue {
+            queue: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Number of blocks currently waiting in the queue.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Pushes a block to the back of the queue. Returns the block back, unmodified, if the queue
+    /// is already at capacity; the caller should apply backpressure (for example, reject the
+    /// import with an error) rather than force the push.
+    pub fn push(&mut self, pending: PendingImport) -> Result<(), PendingImport> {
+        if self.queue.len() >= self.capacity {
+            return Err(pending);
+        }
+        self.queue.push_back(pending);
+        Ok(())
+    }
+
+    /// Looks for the first queued block whose parent matches `is_ready`, removes it from the
+    /// queue, and returns it.
+    ///
+    /// Blocks are scanned in FIFO order but a block isn't required to be at the very front to be
+    /// picked up: a block stuck behind one whose parent isn't ready yet (typically because that
+    /// parent is itself still being verified) must not starve unrelated, already-dispatchable
+    /// blocks such as competing forks.
+    pub fn pop_ready(&mut self, mut is_ready: impl FnMut(&[u8; 32]) -> bool) -> Option<PendingImport> {
+        let index = self.queue.iter().position(|pending| is_ready(&pending.parent_hash))?;
+        self.queue.remove(index)
+    }
+}
+
+/// Shared counter of in-flight verifications, used to bound the worker pool to
+/// `verification_worker_count` concurrent verifications.
+#[derive(Default)]
+pub struct VerifyingCounter(AtomicUsize);
+
+impl VerifyingCounter {
+    pub fn new() -> Arc<Self> {
+        Arc::new(VerifyingCounter(AtomicUsize::new(0)))
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn decrement(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::oneshot;
+
+    fn pending(parent_hash: [u8; 32]) -> PendingImport {
+        let (send_back, _) = oneshot::channel();
+        PendingImport {
+            scale_encoded_header: Vec::new(),
+            body: Vec::new(),
+            parent_hash,
+            send_back,
+        }
+    }
+
+    fn hash(n: u8) -> [u8; 32] {
+        let mut h = [0; 32];
+        h[0] = n;
+        h
+    }
+
+    #[test]
+    fn push_then_pop_ready_round_trips() {
+        let mut queue = UnverifiedQueue::new(4);
+        queue.push(pending(hash(1))).unwrap();
+        assert_eq!(queue.len(), 1);
+
+        let popped = queue.pop_ready(|parent_hash| *parent_hash == hash(1)).unwrap();
+        assert_eq!(popped.parent_hash, hash(1));
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn push_fails_once_at_capacity() {
+        let mut queue = UnverifiedQueue::new(1);
+        queue.push(pending(hash(1))).unwrap();
+        let rejected = queue.push(pending(hash(2))).unwrap_err();
+        assert_eq!(rejected.parent_hash, hash(2));
+    }
+
+    #[test]
+    fn pop_ready_skips_blocks_whose_parent_isnt_ready_yet() {
+        // A block stuck behind one whose parent isn't ready must not starve an unrelated,
+        // already-dispatchable block further back in the queue.
+        let mut queue = UnverifiedQueue::new(4);
+        queue.push(pending(hash(1))).unwrap();
+        queue.push(pending(hash(2))).unwrap();
+
+        let popped = queue.pop_ready(|parent_hash| *parent_hash == hash(2)).unwrap();
+        assert_eq!(popped.parent_hash, hash(2));
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn pop_ready_returns_none_when_nothing_is_ready() {
+        let mut queue = UnverifiedQueue::new(4);
+        queue.push(pending(hash(1))).unwrap();
+        assert!(queue.pop_ready(|parent_hash| *parent_hash == hash(2)).is_none());
+        assert_eq!(queue.len(), 1);
+    }
+}
@@ -0,0 +1,167 @@
+//! Verification of GRANDPA-style finality justifications, and tracking of the authority set that
+//! justifications must be checked against.
+//!
+//! A justification is a set of signed precommits, by the authorities active at the target block,
+//! for a block on the canonical chain. [`verify_justification`] checks the precommits' signatures
+//! and that they represent more than two thirds of the authority set's weighted stake.
+//! [`AuthoritySet`] tracks that set as it evolves, by scanning the consensus digest of every
+//! header on the chain between the previous and new finalized blocks for authority-set-change
+//! items.
+
+use crate::{grandpa, header};
+
+use alloc::vec::Vec;
+
+/// The authority set currently responsible for finalizing blocks.
+#[derive(Debug, Clone)]
+pub struct AuthoritySet {
+    /// Identifier of this set, incremented every time the set changes.
+    pub set_id: u64,
+    /// Authorities and their voting weight.
+    pub authorities: Vec<grandpa::Authority>,
+}
+
+impl AuthoritySet {
+    /// Total weight represented by `justification`'s precommits that come from a member of this
+    /// set. Precommits from an unknown authority, or duplicated for the same authority, count for
+    /// nothing.
+    fn voted_weight(&self, justification: &grandpa::Justification) -> u64 {
+        let mut counted = Vec::with_capacity(self.authorities.len());
+        let mut weight = 0;
+        for precommit in &justification.precommits {
+            if counted.contains(&precommit.authority_id) {
+                continue;
+            }
+            if let Some(authority) = self
+                .authorities
+                .iter()
+                .find(|authority| authority.public_key == precommit.authority_id)
+            {
+                weight += authority.weight;
+                counted.push(precommit.authority_id);
+            }
+        }
+        weight
+    }
+
+    fn total_weight(&self) -> u64 {
+        self.authorities.iter().map(|authority| authority.weight).sum()
+    }
+
+    /// Scans a single imported header's consensus digest for a GRANDPA authority-set-change item,
+    /// updating `self` if it schedules or forces a change effective at this block.
+    pub fn update_from_header_digest(&mut self, header: &header::HeaderRef) {
+        for log in header.digest.logs() {
+            let change = match log {
+                header::DigestItemRef::GrandpaConsensus(
+                    grandpa::ConsensusLogRef::ScheduledChange(change),
+                )
+                | header::DigestItemRef::GrandpaConsensus(
+                    grandpa::ConsensusLogRef::ForcedChange(change),
+                ) => change,
+                _ => continue,
+            };
+
+            self.set_id += 1;
+            self.authorities = change.next_authorities.to_vec();
+        }
+    }
+}
+
+/// Error potentially returned by [`verify_justification`].
+#[derive(Debug, derive_more::Display)]
+pub enum JustificationError {
+    /// Error while decoding the justification.
+    InvalidEncoding(grandpa::DecodeError),
+    /// The justification is for a block other than the one it was checked against.
+    TargetMismatch,
+    /// One of the precommits' signatures doesn't match its claimed authority.
+    BadSignature,
+    /// The justification's valid precommits don't add up to more than two thirds of the authority
+    /// set's weight.
+    NotEnoughWeight,
+}
+
+/// Verifies a GRANDPA justification claiming to finalize `target_hash`/`target_number`, against
+/// the given authority set.
+pub fn verify_justification(
+    scale_encoded_justification: &[u8],
+    target_hash: [u8; 32],
+    target_number: u64,
+    authority_set: &AuthoritySet,
+) -> Result<(), JustificationError> {
+    let justification = grandpa::decode_justification(scale_encoded_justification)
+        .map_err(JustificationError::InvalidEncoding)?;
+
+    if justification.target_hash != target_hash || justification.target_number != target_number {
+        return Err(JustificationError::TargetMismatch);
+    }
+
+    for precommit in &justification.precommits {
+        if !grandpa::verify_precommit_signature(precommit) {
+            return Err(JustificationError::BadSignature);
+        }
+    }
+
+    // Strictly more than two thirds: `3 * voted > 2 * total`.
+    if 3 * authority_set.voted_weight(&justification) <= 2 * authority_set.total_weight() {
+        return Err(JustificationError::NotEnoughWeight);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authority(byte: u8, weight: u64) -> grandpa::Authority {
+        let mut public_key = [0; 32];
+        public_key[0] = byte;
+        grandpa::Authority { public_key, weight }
+    }
+
+    fn precommit(authority_byte: u8) -> grandpa::Precommit {
+        let mut authority_id = [0; 32];
+        authority_id[0] = authority_byte;
+        grandpa::Precommit { authority_id }
+    }
+
+    fn justification(precommits: Vec<grandpa::Precommit>) -> grandpa::Justification {
+        grandpa::Justification {
+            target_hash: [0; 32],
+            target_number: 0,
+            precommits,
+        }
+    }
+
+    #[test]
+    fn voted_weight_sums_known_authorities_only() {
+        let set = AuthoritySet {
+            set_id: 0,
+            authorities: vec![authority(1, 10), authority(2, 20)],
+        };
+        // Precommit 3 doesn't belong to the set and counts for nothing.
+        let justification = justification(vec![precommit(1), precommit(2), precommit(3)]);
+        assert_eq!(set.voted_weight(&justification), 30);
+    }
+
+    #[test]
+    fn voted_weight_ignores_duplicate_precommits_from_the_same_authority() {
+        let set = AuthoritySet {
+            set_id: 0,
+            authorities: vec![authority(1, 10)],
+        };
+        let justification = justification(vec![precommit(1), precommit(1)]);
+        assert_eq!(set.voted_weight(&justification), 10);
+    }
+
+    #[test]
+    fn total_weight_sums_every_authority_regardless_of_precommits() {
+        let set = AuthoritySet {
+            set_id: 0,
+            authorities: vec![authority(1, 10), authority(2, 20), authority(3, 5)],
+        };
+        assert_eq!(set.total_weight(), 35);
+    }
+}
@@ -0,0 +1,104 @@
+//! Broadcast of notifications to downstream subscribers every time a block is successfully
+//! imported, analogous to Ethereum's `ChainNotify`.
+//!
+//! Subscribers are handed an `mpsc::Receiver` by `ToBlockImport::SubscribeImported`; the commit
+//! stage of [`run_block_import_task`](super::block_import_task::run_block_import_task) pushes an
+//! [`ImportedBlockNotification`] to every live subscriber after each successful database write.
+//! A subscriber that drops its receiver is removed the next time a notification is sent, and one
+//! that stops polling it is removed the first time a notification finds its channel full; neither
+//! is proactively detected.
+
+use alloc::vec::Vec;
+use futures::channel::mpsc;
+
+/// Notification sent to every live subscriber after a block has been successfully written to the
+/// database.
+#[derive(Debug, Clone)]
+pub struct ImportedBlockNotification {
+    /// Hash of the imported block.
+    pub hash: [u8; 32],
+    /// Height of the imported block.
+    pub number: u64,
+    /// SCALE-encoded header of the imported block.
+    pub scale_encoded_header: Vec<u8>,
+    /// Whether this block became the new best block.
+    pub is_new_best: bool,
+    /// Keys whose value was created, removed, or modified by this block.
+    pub modified_keys: Vec<Vec<u8>>,
+    /// Hashes of the blocks, if any, that were retracted in order to make this block part of the
+    /// best chain. Empty if this block didn't become the new best block.
+    pub retracted: Vec<[u8; 32]>,
+    /// Hashes of the blocks, if any, that were enacted on top of the common ancestor in order to
+    /// make this block part of the best chain, including this block itself. Empty if this block
+    /// didn't become the new best block.
+    pub enacted: Vec<[u8; 32]>,
+}
+
+/// Bounded channel capacity used for each subscriber. Generous enough that a subscriber briefly
+/// slow to poll doesn't miss a notification; one that falls behind for longer is assumed gone.
+const SUBSCRIBER_CHANNEL_CAPACITY: usize = 64;
+
+/// Live set of subscribers to import notifications.
+#[derive(Default)]
+pub struct Subscribers {
+    senders: Vec<mpsc::Sender<ImportedBlockNotification>>,
+}
+
+impl Subscribers {
+    /// Creates an empty set of subscribers.
+    pub fn new() -> Self {
+        Subscribers {
+            senders: Vec::new(),
+        }
+    }
+
+    /// Registers a new subscriber, returning the receiving end to hand back to the caller.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<ImportedBlockNotification> {
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_CHANNEL_CAPACITY);
+        self.senders.push(tx);
+        rx
+    }
+
+    /// Sends `notification` to every live subscriber, lazily dropping any whose receiver has been
+    /// closed, or that is too far behind to keep up.
+    pub fn notify(&mut self, notification: ImportedBlockNotification) {
+        self.senders
+            .retain_mut(|sender| sender.try_send(notification.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notification() -> ImportedBlockNotification {
+        ImportedBlockNotification {
+            hash: [0; 32],
+            number: 0,
+            scale_encoded_header: Vec::new(),
+            is_new_best: true,
+            modified_keys: Vec::new(),
+            retracted: Vec::new(),
+            enacted: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn subscribe_then_notify_delivers_to_the_receiver() {
+        let mut subscribers = Subscribers::new();
+        let mut receiver = subscribers.subscribe();
+        subscribers.notify(notification());
+        assert!(receiver.try_next().unwrap().is_some());
+    }
+
+    #[test]
+    fn notify_drops_subscribers_whose_receiver_was_closed() {
+        let mut subscribers = Subscribers::new();
+        let receiver = subscribers.subscribe();
+        drop(receiver);
+
+        subscribers.notify(notification());
+
+        assert!(subscribers.senders.is_empty());
+    }
+}
@@ -0,0 +1,122 @@
+//! Support for bootstrapping the database from a full snapshot of a block's top trie, rather than
+//! executing every block back to genesis. Inspired by Parity's warp sync.
+//!
+//! A snapshot is received in [`ToBlockImport::ImportStateSnapshot`] chunks, each carrying a batch
+//! of top-trie key/value pairs. Chunks are persisted to the database as soon as they arrive, so
+//! that a node restart doesn't lose already-received data; the sender is expected to resume
+//! sending from [`PendingSnapshot::received_keys`] onwards rather than starting over. Once the
+//! final chunk is received, the accumulated entries are checked against the target block's
+//! `state_root` (see [`super::block_import_task::run_block_import_task`]) and, only if they
+//! match, atomically installed as the new best block.
+//!
+//! [`ToBlockImport::ImportStateSnapshot`]: super::block_import_task::ToBlockImport::ImportStateSnapshot
+
+use alloc::collections::BTreeMap;
+
+/// State of a snapshot whose chunks are still being received.
+#[derive(Debug, Clone)]
+pub struct PendingSnapshot {
+    /// SCALE-encoded header of the block the snapshot is for.
+    pub scale_encoded_header: Vec<u8>,
+    /// Top-trie entries received so far.
+    pub accumulated: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Running total of `key.len() + value.len()` across `accumulated`, kept up to date
+    /// incrementally so that callers can cheaply enforce a budget on every chunk rather than only
+    /// once the snapshot is supposedly complete.
+    accumulated_bytes: usize,
+}
+
+impl PendingSnapshot {
+    /// Creates a new, empty, in-progress snapshot for the given block.
+    pub fn new(scale_encoded_header: Vec<u8>) -> Self {
+        PendingSnapshot {
+            scale_encoded_header,
+            accumulated: BTreeMap::new(),
+            accumulated_bytes: 0,
+        }
+    }
+
+    /// Number of entries received so far. Reported back to the sender so that, after a restart,
+    /// it knows to resume sending chunks from this point rather than from the start.
+    pub fn received_keys(&self) -> usize {
+        self.accumulated.len()
+    }
+
+    /// Total size, in bytes, of the entries received so far. Compared against
+    /// [`super::block_import_task::Config::max_snapshot_bytes`] after every chunk so that a peer
+    /// that never sends a final chunk (or sends unbounded garbage) can't grow memory and on-disk
+    /// storage without limit.
+    pub fn accumulated_bytes(&self) -> usize {
+        self.accumulated_bytes
+    }
+
+    /// Merges a newly-received chunk into the accumulated entries.
+    pub fn extend(&mut self, chunk: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>) {
+        for (key, value) in chunk {
+            match self.accumulated.insert(key.clone(), value) {
+                Some(previous) => {
+                    let new_len = self.accumulated[&key].len();
+                    self.accumulated_bytes = self.accumulated_bytes + new_len - previous.len();
+                }
+                None => {
+                    self.accumulated_bytes += key.len() + self.accumulated[&key].len();
+                }
+            }
+        }
+    }
+}
+
+/// Outcome of handling one `ImportStateSnapshot` message.
+#[derive(Debug, Clone)]
+pub enum ImportStateSnapshotOutcome {
+    /// The chunk was accepted, but more are still expected before the snapshot is complete.
+    Pending {
+        /// Total number of entries received for this snapshot so far, across all chunks.
+        received_keys: usize,
+    },
+    /// This was the last chunk, the recomputed root matched the target header's `state_root`,
+    /// and the snapshot has been installed as the new best block.
+    Installed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extend_accumulates_bytes_for_new_keys() {
+        let mut snapshot = PendingSnapshot::new(Vec::new());
+        snapshot.extend(vec![
+            (b"k0".to_vec(), b"v0".to_vec()),
+            (b"k1".to_vec(), b"v1".to_vec()),
+        ]);
+
+        assert_eq!(snapshot.received_keys(), 2);
+        assert_eq!(
+            snapshot.accumulated_bytes(),
+            "k0".len() + "v0".len() + "k1".len() + "v1".len()
+        );
+    }
+
+    #[test]
+    fn extend_grows_bytes_when_overwriting_with_a_longer_value() {
+        let mut snapshot = PendingSnapshot::new(Vec::new());
+        snapshot.extend(vec![(b"key".to_vec(), b"short".to_vec())]);
+        snapshot.extend(vec![(b"key".to_vec(), b"a-much-longer-value".to_vec())]);
+
+        assert_eq!(snapshot.received_keys(), 1);
+        assert_eq!(
+            snapshot.accumulated_bytes(),
+            "key".len() + "a-much-longer-value".len()
+        );
+    }
+
+    #[test]
+    fn extend_shrinks_bytes_when_overwriting_with_a_shorter_value() {
+        let mut snapshot = PendingSnapshot::new(Vec::new());
+        snapshot.extend(vec![(b"key".to_vec(), b"a-much-longer-value".to_vec())]);
+        snapshot.extend(vec![(b"key".to_vec(), b"short".to_vec())]);
+
+        assert_eq!(snapshot.accumulated_bytes(), "key".len() + "short".len());
+    }
+}
@@ -0,0 +1,269 @@
+//! Bounded, eviction-capable cache of top-trie key/value pairs sitting in front of the database.
+//!
+//! Rather than loading a chain's entire state into memory, [`StorageCache`] keeps only the most
+//! recently read or written entries, up to a configurable byte budget, and callers fall back to
+//! the database on a miss. The cache is deliberately unaware of forks or uncommitted blocks: it
+//! only ever holds values as of the database's current best block. The caller is responsible for
+//! layering a block's (or a tree route's) pending changes on top of it — see [`resolve`], which
+//! checks such an overlay first, then the cache, then the database.
+
+use crate::database;
+
+use alloc::collections::BTreeMap;
+use parking_lot::Mutex;
+
+/// A cached entry, along with how long ago it was last touched, used to pick what to evict when
+/// the cache grows past its byte budget.
+struct Entry {
+    value: Vec<u8>,
+    /// Monotonically increasing "clock" value, bumped on every access. The entry with the lowest
+    /// value is the one evicted first.
+    last_used: u64,
+}
+
+/// Memory-budgeted cache of top-trie key/value pairs, as of the database's current best block.
+pub struct StorageCache {
+    entries: BTreeMap<Vec<u8>, Entry>,
+    /// Running total of `key.len() + value.len()` across `entries`, compared against
+    /// `byte_budget` to decide when to evict.
+    total_bytes: usize,
+    byte_budget: usize,
+    clock: u64,
+}
+
+impl StorageCache {
+    /// Creates an empty cache that evicts entries once they'd make it exceed `byte_budget`.
+    pub fn new(byte_budget: usize) -> Self {
+        StorageCache {
+            entries: BTreeMap::new(),
+            total_bytes: 0,
+            byte_budget,
+            clock: 0,
+        }
+    }
+
+    /// Returns the cached value for `key`, if present, marking it as freshly used.
+    pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.clock += 1;
+        let clock = self.clock;
+        let entry = self.entries.get_mut(key)?;
+        entry.last_used = clock;
+        Some(entry.value.clone())
+    }
+
+    /// Inserts or updates a cached entry, evicting the least-recently-used entries if needed to
+    /// stay within the byte budget.
+    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.clock += 1;
+        let added_bytes = key.len() + value.len();
+
+        if let Some(previous) = self.entries.insert(
+            key.clone(),
+            Entry {
+                value,
+                last_used: self.clock,
+            },
+        ) {
+            self.total_bytes -= previous.value.len() + key.len();
+        }
+        self.total_bytes += added_bytes;
+
+        // TODO: this is an O(n) scan of every cached entry; a real LRU would keep an intrusive
+        // linked list of entries ordered by last use instead
+        while self.total_bytes > self.byte_budget {
+            let oldest_key = match self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            {
+                Some(key) => key,
+                None => break,
+            };
+            self.remove(&oldest_key);
+        }
+    }
+
+    /// Removes a key from the cache, typically because the underlying database entry changed.
+    pub fn remove(&mut self, key: &[u8]) {
+        if let Some(previous) = self.entries.remove(key) {
+            self.total_bytes -= previous.value.len() + key.len();
+        }
+    }
+
+    /// Empties the cache, typically because the database's best block jumped to an unrelated
+    /// state (see `ToBlockImport::ImportStateSnapshot`) and cached entries can no longer be
+    /// assumed to still apply.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.total_bytes = 0;
+    }
+}
+
+/// Resolves the value of `key`, checking `overlay` first (a map of uncommitted changes, such as
+/// the diffs of a tree route being replayed, where `None` means the key was deleted), then
+/// `cache`, then falling through to the database and populating `cache` with the result.
+pub fn resolve(
+    key: &[u8],
+    overlay: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    cache: &Mutex<StorageCache>,
+    database: &database::Database,
+) -> Option<Vec<u8>> {
+    if let Some(value) = overlay.get(key) {
+        return value.clone();
+    }
+
+    if let Some(value) = cache.lock().get(key) {
+        return Some(value);
+    }
+
+    let best_block = database.best_block_hash().unwrap();
+    let value = database.storage_top_trie_get(best_block, key).unwrap()?;
+    let value = value.to_vec();
+    cache.lock().insert(key.to_vec(), value.clone());
+    Some(value)
+}
+
+/// Returns every key starting with `prefix`, as seen through `overlay` layered on top of the
+/// database.
+///
+/// Unlike [`resolve`], this never consults the point-lookup cache: a cache that only holds a
+/// bounded subset of entries can't reliably tell whether it has *every* key in a given range, so
+/// doing so risks silently omitting keys that were evicted. The database is authoritative here.
+// TODO: this re-fetches and filters the entire key set on every call; a database able to answer
+// prefix queries directly would avoid that
+pub fn keys_prefix(
+    prefix: &[u8],
+    overlay: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    database: &database::Database,
+) -> Vec<Vec<u8>> {
+    let best_block = database.best_block_hash().unwrap();
+
+    let mut keys: BTreeMap<Vec<u8>, ()> = database
+        .storage_top_trie_keys(best_block)
+        .unwrap()
+        .into_iter()
+        .map(|key| key.to_vec())
+        .filter(|key| key.starts_with(prefix))
+        .map(|key| (key, ()))
+        .collect();
+
+    for (key, value) in overlay {
+        if !key.starts_with(prefix) {
+            continue;
+        }
+        match value {
+            Some(_) => {
+                keys.insert(key.clone(), ());
+            }
+            None => {
+                keys.remove(key);
+            }
+        }
+    }
+
+    keys.into_keys().collect()
+}
+
+/// Returns the key that immediately follows `key`, as seen through `overlay` layered on top of
+/// the database. Like [`keys_prefix`], this doesn't consult the point-lookup cache.
+pub fn next_key(
+    key: &[u8],
+    overlay: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    database: &database::Database,
+) -> Option<Vec<u8>> {
+    let best_block = database.best_block_hash().unwrap();
+
+    let db_next = database
+        .storage_top_trie_keys(best_block)
+        .unwrap()
+        .into_iter()
+        .map(|k| k.to_vec())
+        .filter(|k| k.as_slice() > key)
+        .filter(|k| !matches!(overlay.get(k), Some(None)))
+        .min();
+
+    let overlay_next = overlay
+        .iter()
+        .filter(|(k, v)| k.as_slice() > key && v.is_some())
+        .map(|(k, _)| k.clone())
+        .min();
+
+    match (db_next, overlay_next) {
+        (Some(a), Some(b)) => Some(core::cmp::min(a, b)),
+        (Some(a), None) | (None, Some(a)) => Some(a),
+        (None, None) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_returns_none_when_absent() {
+        let mut cache = StorageCache::new(1024);
+        assert_eq!(cache.get(b"key"), None);
+    }
+
+    #[test]
+    fn insert_then_get_round_trips() {
+        let mut cache = StorageCache::new(1024);
+        cache.insert(b"key".to_vec(), b"value".to_vec());
+        assert_eq!(cache.get(b"key"), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn insert_overwrites_and_updates_byte_accounting() {
+        let mut cache = StorageCache::new(1024);
+        cache.insert(b"key".to_vec(), b"value".to_vec());
+        cache.insert(b"key".to_vec(), b"new-value".to_vec());
+        assert_eq!(cache.get(b"key"), Some(b"new-value".to_vec()));
+        assert_eq!(cache.total_bytes, "key".len() + "new-value".len());
+    }
+
+    #[test]
+    fn remove_drops_the_entry() {
+        let mut cache = StorageCache::new(1024);
+        cache.insert(b"key".to_vec(), b"value".to_vec());
+        cache.remove(b"key");
+        assert_eq!(cache.get(b"key"), None);
+        assert_eq!(cache.total_bytes, 0);
+    }
+
+    #[test]
+    fn clear_empties_the_cache() {
+        let mut cache = StorageCache::new(1024);
+        cache.insert(b"key".to_vec(), b"value".to_vec());
+        cache.clear();
+        assert_eq!(cache.get(b"key"), None);
+        assert_eq!(cache.total_bytes, 0);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_entry_once_over_budget() {
+        // Budget only large enough for one of these two 4-byte ("k0"+"v0") entries.
+        let mut cache = StorageCache::new(6);
+        cache.insert(b"k0".to_vec(), b"v0".to_vec());
+        cache.insert(b"k1".to_vec(), b"v1".to_vec());
+
+        // "k0" was inserted first and never touched again since, so it's the one evicted.
+        assert_eq!(cache.get(b"k0"), None);
+        assert_eq!(cache.get(b"k1"), Some(b"v1".to_vec()));
+    }
+
+    #[test]
+    fn accessing_an_entry_protects_it_from_eviction() {
+        // Budget large enough for two of these 4-byte entries, but not three.
+        let mut cache = StorageCache::new(8);
+        cache.insert(b"k0".to_vec(), b"v0".to_vec());
+        cache.insert(b"k1".to_vec(), b"v1".to_vec());
+        // Touch "k0" so it's more recently used than "k1" by the time "k2" comes in.
+        assert_eq!(cache.get(b"k0"), Some(b"v0".to_vec()));
+        cache.insert(b"k2".to_vec(), b"v2".to_vec());
+
+        assert_eq!(cache.get(b"k0"), Some(b"v0".to_vec()));
+        assert_eq!(cache.get(b"k1"), None);
+        assert_eq!(cache.get(b"k2"), Some(b"v2".to_vec()));
+    }
+}
@@ -0,0 +1,243 @@
+//! In-memory tree of recently-imported block headers.
+//!
+//! This lets the block import task accept side-chain imports (blocks whose parent isn't the
+//! current best block) rather than rejecting them outright, and compute the route to follow when
+//! a heavier branch overtakes the current best chain. Modeled after Parity Ethereum's
+//! `TreeRoute`/`ImportRoute`.
+
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+
+/// A block that has been imported and is tracked by the [`ImportedBlocksTree`], whether or not it
+/// is part of the current best chain.
+#[derive(Debug, Clone)]
+pub struct TreeNode {
+    /// SCALE-encoded header of this block.
+    pub scale_encoded_header: Vec<u8>,
+    /// Hash of the parent of this block.
+    pub parent_hash: [u8; 32],
+    /// Height of the block.
+    pub number: u64,
+    /// Cumulative weight of the chain ending with this block, used to decide whether a branch
+    /// should become the new best chain. For BABE chains this is the sum of the primary-slot
+    /// claims of every ancestor (including this block); chains that don't report that
+    /// information can just use the block number, as a longer chain is then always heavier.
+    pub cumulative_weight: u64,
+    /// Storage changes performed by this block, as returned by the verification process: for
+    /// every modified key, its new value (`None` if the key was removed).
+    pub storage_top_trie_changes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// For every key in [`TreeNode::storage_top_trie_changes`], its value immediately before this
+    /// block was applied (`None` if the key didn't exist yet). Used to undo this block's changes
+    /// when it is retracted.
+    pub storage_top_trie_previous_values: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+/// Tree of recently-imported block headers, keyed by hash.
+///
+/// Only blocks that might still be needed to compute a future tree route have to be kept around;
+/// call [`ImportedBlocksTree::prune_finalized`] once finality has moved past them.
+#[derive(Debug, Default)]
+pub struct ImportedBlocksTree {
+    nodes: BTreeMap<[u8; 32], TreeNode>,
+}
+
+impl ImportedBlocksTree {
+    /// Creates a new empty tree.
+    pub fn empty() -> Self {
+        ImportedBlocksTree {
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    /// Returns true if the given hash is known to the tree.
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.nodes.contains_key(hash)
+    }
+
+    /// Returns the node matching the given hash, if any.
+    pub fn get(&self, hash: &[u8; 32]) -> Option<&TreeNode> {
+        self.nodes.get(hash)
+    }
+
+    /// Inserts a newly-imported block into the tree.
+    pub fn insert(
+        &mut self,
+        hash: [u8; 32],
+        scale_encoded_header: Vec<u8>,
+        parent_hash: [u8; 32],
+        number: u64,
+        cumulative_weight: u64,
+        storage_top_trie_changes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        storage_top_trie_previous_values: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    ) {
+        self.nodes.insert(
+            hash,
+            TreeNode {
+                scale_encoded_header,
+                parent_hash,
+                number,
+                cumulative_weight,
+                storage_top_trie_changes,
+                storage_top_trie_previous_values,
+            },
+        );
+    }
+
+    /// Returns the cumulative weight of the given block, or `0` if it isn't tracked (typically
+    /// because it is `database_best`).
+    pub fn cumulative_weight(&self, hash: &[u8; 32]) -> u64 {
+        self.nodes
+            .get(hash)
+            .map(|n| n.cumulative_weight)
+            .unwrap_or(0)
+    }
+
+    /// Drops all tracked blocks at or below `finalized_number`, other than `finalized_hash`
+    /// itself. Must be called after the finalized block actually moves, so that the tree doesn't
+    /// grow forever.
+    pub fn prune_finalized(&mut self, finalized_hash: [u8; 32], finalized_number: u64) {
+        self.nodes
+            .retain(|hash, node| *hash == finalized_hash || node.number > finalized_number);
+    }
+
+    /// Computes the [`TreeRoute`] to follow in order to move the chain head from `from` to `to`.
+    ///
+    /// `database_best` is the root the tree is built on top of (typically the database's best, or
+    /// finalized, block at the time the tree was created); both `from` and `to` are allowed to be
+    /// equal to it.
+    ///
+    /// Returns `None` if `from` or `to` can't be traced back to `database_best` using the
+    /// information tracked by this tree, which shouldn't happen for hashes that were passed to
+    /// a previous call to [`ImportedBlocksTree::insert`].
+    pub fn route(&self, from: [u8; 32], to: [u8; 32], database_best: [u8; 32]) -> Option<TreeRoute> {
+        // Ancestry of `from`, starting with `from` itself and ending with `database_best`.
+        let mut from_chain = vec![from];
+        let mut cursor = from;
+        while cursor != database_best {
+            cursor = self.nodes.get(&cursor)?.parent_hash;
+            from_chain.push(cursor);
+        }
+
+        // Walk up from `to` until we hit a block that is also part of `from`'s ancestry. Because
+        // `database_best` always is, this is guaranteed to terminate.
+        let mut enacted = Vec::new();
+        let mut cursor = to;
+        loop {
+            if let Some(split) = from_chain.iter().position(|h| *h == cursor) {
+                enacted.reverse();
+                return Some(TreeRoute {
+                    common_ancestor: cursor,
+                    retracted: from_chain[..split].to_vec(),
+                    enacted,
+                });
+            }
+            enacted.push(cursor);
+            cursor = self.nodes.get(&cursor)?.parent_hash;
+        }
+    }
+}
+
+/// Route to follow in order to move the chain head from one block to another.
+#[derive(Debug, Clone)]
+pub struct TreeRoute {
+    /// Hash of the common ancestor of the old and new best blocks.
+    pub common_ancestor: [u8; 32],
+    /// Blocks to retract, ordered from the old best block down to (but excluding) the common
+    /// ancestor.
+    pub retracted: Vec<[u8; 32]>,
+    /// Blocks to enact, ordered from right after the common ancestor up to (and including) the
+    /// new best block.
+    pub enacted: Vec<[u8; 32]>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(n: u8) -> [u8; 32] {
+        let mut h = [0; 32];
+        h[0] = n;
+        h
+    }
+
+    fn insert_linear(tree: &mut ImportedBlocksTree, hash: [u8; 32], parent_hash: [u8; 32], number: u64) {
+        tree.insert(
+            hash,
+            Vec::new(),
+            parent_hash,
+            number,
+            number,
+            BTreeMap::new(),
+            BTreeMap::new(),
+        );
+    }
+
+    #[test]
+    fn route_simple_linear_extension() {
+        let root = hash(0);
+        let mut tree = ImportedBlocksTree::empty();
+        insert_linear(&mut tree, hash(1), root, 1);
+
+        let route = tree.route(root, hash(1), root).unwrap();
+        assert_eq!(route.common_ancestor, root);
+        assert!(route.retracted.is_empty());
+        assert_eq!(route.enacted, vec![hash(1)]);
+    }
+
+    #[test]
+    fn route_reorg_across_a_fork() {
+        // root -> 1 -> 2 (old best)
+        //      -> 1 -> 3 -> 4 (new best)
+        let root = hash(0);
+        let mut tree = ImportedBlocksTree::empty();
+        insert_linear(&mut tree, hash(1), root, 1);
+        insert_linear(&mut tree, hash(2), hash(1), 2);
+        insert_linear(&mut tree, hash(3), hash(1), 2);
+        insert_linear(&mut tree, hash(4), hash(3), 3);
+
+        let route = tree.route(hash(2), hash(4), root).unwrap();
+        assert_eq!(route.common_ancestor, hash(1));
+        assert_eq!(route.retracted, vec![hash(2)]);
+        assert_eq!(route.enacted, vec![hash(3), hash(4)]);
+    }
+
+    #[test]
+    fn route_unknown_endpoint_returns_none() {
+        let root = hash(0);
+        let tree = ImportedBlocksTree::empty();
+        assert!(tree.route(root, hash(42), root).is_none());
+    }
+
+    #[test]
+    fn prune_finalized_drops_old_blocks_but_keeps_the_finalized_one() {
+        let root = hash(0);
+        let mut tree = ImportedBlocksTree::empty();
+        insert_linear(&mut tree, hash(1), root, 1);
+        insert_linear(&mut tree, hash(2), hash(1), 2);
+        insert_linear(&mut tree, hash(3), hash(2), 3);
+
+        tree.prune_finalized(hash(2), 2);
+
+        assert!(!tree.contains(&hash(1)));
+        assert!(tree.contains(&hash(2)));
+        assert!(tree.contains(&hash(3)));
+    }
+
+    #[test]
+    fn prune_finalized_breaks_routes_through_the_old_root() {
+        let root = hash(0);
+        let mut tree = ImportedBlocksTree::empty();
+        insert_linear(&mut tree, hash(1), root, 1);
+        insert_linear(&mut tree, hash(2), hash(1), 2);
+        insert_linear(&mut tree, hash(3), hash(2), 3);
+
+        tree.prune_finalized(hash(2), 2);
+
+        // `hash(1)` has been pruned, so `route` can no longer walk back to the old `root`:
+        // callers must advance their own idea of the tree's root to the finalized block in
+        // lockstep with pruning past it (see `run_block_import_task`'s `tree_root`).
+        assert!(tree.route(hash(3), hash(3), root).is_none());
+
+        // Using the finalized block as the new root works fine.
+        assert!(tree.route(hash(3), hash(3), hash(2)).is_some());
+    }
+}